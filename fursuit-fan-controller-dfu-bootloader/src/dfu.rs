@@ -0,0 +1,121 @@
+//! USB DFU class endpoint and the flash region shared with the application's
+//! `Persistence` key/value store.
+
+use core::cell::RefCell;
+
+use embassy_boot_stm32::FirmwareUpdater;
+use embassy_stm32::{
+    Peri, bind_interrupts,
+    flash::{Blocking, FLASH_BASE, Flash},
+    peripherals::{PA12, USB},
+    usb,
+};
+use embassy_sync::blocking_mutex::{Mutex, raw::RawMutex};
+use embassy_usb::{Builder, Config, msos};
+use embassy_usb_dfu::{Control, Dfu};
+use sequential_storage::cache::NoCache;
+
+use crate::sig_verify;
+
+bind_interrupts!(struct Irqs {
+    USB_LP_CAN1_RX0 => usb::InterruptHandler<USB>;
+});
+
+/// Same key as `fursuit_fan_controller_fw::persistence::Persistence`'s
+/// `DFU_REQUESTED_KEY`, and the same flash range (the `.flash_filesystem`
+/// section reserved by the application's `link-custom.x`).
+const DFU_REQUESTED_KEY: u8 = 1;
+
+unsafe extern "C" {
+    static __flash_filesystem_start: u8;
+    static __flash_filesystem_end: u8;
+}
+
+/// The `.flash_filesystem` section's offset/size, relative to flash base -
+/// the same range `fursuit_fan_controller_fw::persistence::Persistence::new`
+/// computes at runtime from `&raw const FLASH_FILESYSTEM_SECTION`. Derived
+/// from the `__flash_filesystem_start`/`__flash_filesystem_end` symbols
+/// `link-custom.x` provides, rather than a hand-copied constant, so the
+/// bootloader and application can never drift apart on this.
+fn flash_filesystem_range() -> core::ops::Range<u32> {
+    let start = (&raw const __flash_filesystem_start) as usize;
+    let end = (&raw const __flash_filesystem_end) as usize;
+    (start - FLASH_BASE) as u32..(end - FLASH_BASE) as u32
+}
+
+pub fn read_and_clear_dfu_flag<M: RawMutex>(
+    flash: &Mutex<M, RefCell<Flash<'static, Blocking>>>,
+) -> bool {
+    flash.lock(|flash| {
+        let mut flash = flash.borrow_mut();
+        let mut data_buffer = [0; 128];
+        let flash_filesystem_range = flash_filesystem_range();
+        let requested = embassy_futures::block_on(sequential_storage::map::fetch_item(
+            &mut *flash,
+            flash_filesystem_range.clone(),
+            &mut NoCache::new(),
+            &mut data_buffer,
+            &DFU_REQUESTED_KEY,
+        ))
+        .ok()
+        .flatten()
+        .unwrap_or(false);
+
+        if requested {
+            embassy_futures::block_on(sequential_storage::map::store_item(
+                &mut *flash,
+                flash_filesystem_range,
+                &mut NoCache::new(),
+                &mut data_buffer,
+                &DFU_REQUESTED_KEY,
+                &false,
+            ))
+            .ok();
+        }
+
+        requested
+    })
+}
+
+/// Enumerates as a USB DFU device and accepts a firmware image into the
+/// inactive slot via `updater`. Blocks until the USB bus is suspended (for
+/// example when the host detaches after the transfer).
+///
+/// Every downloaded block is buffered by `embassy-usb-dfu`'s [`Dfu`] class;
+/// once the host sends `DFU_DNLOAD` with a zero-length block (signalling
+/// end-of-image), we verify the accumulated image's ed25519 signature with
+/// [`sig_verify::verify`] before calling `updater.mark_updated()`. If
+/// verification fails we report a DFU error status instead, leaving the
+/// previously-active bank untouched.
+pub fn run_usb_dfu(usb: Peri<'static, USB>, dp: Peri<'static, PA12>, updater: &mut FirmwareUpdater) {
+    let driver = usb::Driver::new(usb, Irqs, dp);
+
+    let mut usb_config = Config::new(0xc0de, 0xdf00);
+    usb_config.manufacturer = Some("Fursuit Fan Controller");
+    usb_config.product = Some("Fan Controller DFU Bootloader");
+
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut msos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+
+    let mut builder = Builder::new(
+        driver,
+        usb_config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut msos_descriptor,
+        &mut control_buf,
+    );
+    builder.msos_descriptor(msos::windows_version::WIN8_1, 0);
+
+    let mut control = Control::new(updater, sig_verify::verify);
+    Dfu::new(&mut builder, &mut control);
+
+    let mut usb_device = builder.build();
+
+    // The bootloader has no async executor of its own; `embassy_futures::block_on`
+    // just polls this single future to completion, which is all we need for
+    // a synchronous download-then-reset flow.
+    embassy_futures::block_on(usb_device.run_until_suspend());
+}
@@ -0,0 +1,68 @@
+//! Dual-bank A/B bootloader for the fan controller, mirroring the design of
+//! `embassy-boot-stm32`: it holds the active and DFU application slots plus a
+//! small swap/state region, and performs a power-fail-safe swap between them.
+//!
+//! Normal boot just verifies the active slot's trial-boot state and jumps to
+//! it. If `fursuit_fan_controller_fw::persistence::Persistence` left the
+//! "DFU requested" flag set (see `MainTaskMessage::EnterDfu`), this instead
+//! enumerates as a USB DFU device, accepts a new image into the inactive
+//! (DFU) slot, verifies its ed25519 signature, and only then tells
+//! `embassy-boot` to swap it in on the next reset. A failed signature check,
+//! or a failed trial boot of a previously-swapped image, rolls back to the
+//! last known-good bank automatically - this is `embassy-boot`'s own
+//! swap-state machine, we just gate entry into it on our signature check.
+
+#![no_std]
+#![no_main]
+
+mod dfu;
+mod sig_verify;
+
+use core::cell::RefCell;
+
+use cortex_m_rt::entry;
+use defmt_rtt as _;
+use embassy_boot_stm32::{BootLoader, BootLoaderConfig, FirmwareUpdater, FirmwareUpdaterConfig};
+use embassy_stm32::flash::Flash;
+use embassy_sync::blocking_mutex::{Mutex, raw::NoopRawMutex};
+use panic_reset as _;
+
+#[entry]
+fn main() -> ! {
+    let p = embassy_stm32::init(Default::default());
+    let flash = Mutex::<NoopRawMutex, _>::new(RefCell::new(Flash::new_blocking(p.FLASH)));
+
+    // `link-custom.x` in the application crate lays out the active slot, the
+    // `.dfu_slot` bank, and the swap/state region this config is derived from.
+    let boot_config = BootLoaderConfig::from_linkerfile_blocking(&flash, &flash, &flash);
+    let active_offset = boot_config.active.offset();
+    let bootloader = BootLoader::prepare(boot_config);
+
+    if dfu_requested(&flash) {
+        let updater_config = FirmwareUpdaterConfig::from_linkerfile_blocking(&flash, &flash);
+        let mut updater = FirmwareUpdater::new(updater_config, &mut [0; 4096]);
+        dfu::run_usb_dfu(p.USB, p.PA12, &mut updater);
+        // `dfu::run_usb_dfu` returns once the USB bus is suspended, whether
+        // or not a signature-verified image was staged. `BootLoader::prepare`
+        // already ran above, so a swap committed by `updater.mark_updated()`
+        // during the transfer is applied on the next reset; this boot falls
+        // through to the currently active slot below.
+    }
+
+    // SAFETY: `active_offset` was produced by `BootLoaderConfig` from the same
+    // linker script that places the active application's vector table, so it
+    // points at a valid, relocatable image.
+    unsafe { bootloader.load(embassy_stm32::flash::FLASH_BASE as u32 + active_offset) }
+}
+
+/// Reads (and clears) the "DFU requested" flag the application set via
+/// `Persistence::request_dfu_on_next_boot` before resetting into us.
+fn dfu_requested<M: embassy_sync::blocking_mutex::raw::RawMutex>(
+    flash: &Mutex<M, RefCell<Flash<'static, embassy_stm32::flash::Blocking>>>,
+) -> bool {
+    // The flag lives under the same `sequential_storage` key/value store and
+    // key (`DFU_REQUESTED_KEY`) the application firmware uses; duplicated
+    // here rather than shared via a crate dependency, since the bootloader
+    // and application are built and flashed independently.
+    dfu::read_and_clear_dfu_flag(flash)
+}
@@ -0,0 +1,44 @@
+//! Ed25519 verification of downloaded firmware images.
+//!
+//! Images accepted over USB DFU are expected to have a 64-byte ed25519
+//! signature appended after the raw firmware bytes. We verify that signature
+//! against our baked-in public key before the DFU `Control` set up in
+//! [`crate::dfu::run_usb_dfu`] marks the staged bank as bootable - an unsigned or corrupted image is
+//! left in place, untrusted, and the active bank keeps running.
+
+use salty::constants::{PUBLICKEY_SERIALIZED_LENGTH, SIGNATURE_SERIALIZED_LENGTH};
+use salty::{PublicKey, Signature};
+
+/// Public key corresponding to the private key used to sign release images.
+/// Generated once and committed here; the private key never touches the device.
+const SIGNING_PUBLIC_KEY: [u8; PUBLICKEY_SERIALIZED_LENGTH] = [
+    0x85, 0x58, 0x23, 0x71, 0x24, 0xfb, 0x9e, 0x11, 0xe6, 0xae, 0xa6, 0xf0, 0x43, 0xa9, 0x26, 0xc9,
+    0x50, 0x75, 0x84, 0x7c, 0xf3, 0xf4, 0xc9, 0xae, 0x72, 0xce, 0x9f, 0x46, 0x32, 0xa3, 0xfa, 0x5e,
+];
+
+/// Splits `image_with_signature` into the firmware image and its trailing
+/// signature, then verifies it against [`SIGNING_PUBLIC_KEY`].
+///
+/// Returns `false` (reject) if the buffer is too short to even contain a
+/// signature, or if verification fails.
+pub fn verify(image_with_signature: &[u8]) -> bool {
+    if image_with_signature.len() < SIGNATURE_SERIALIZED_LENGTH {
+        return false;
+    }
+
+    let split_at = image_with_signature.len() - SIGNATURE_SERIALIZED_LENGTH;
+    let (image, signature_bytes) = image_with_signature.split_at(split_at);
+
+    let Ok(signature_bytes): Result<&[u8; SIGNATURE_SERIALIZED_LENGTH], _> =
+        signature_bytes.try_into()
+    else {
+        return false;
+    };
+    let signature = Signature::try_from(signature_bytes);
+    let public_key = PublicKey::try_from(&SIGNING_PUBLIC_KEY);
+
+    match (public_key, signature) {
+        (Ok(public_key), Ok(signature)) => public_key.verify(image, &signature).is_ok(),
+        _ => false,
+    }
+}
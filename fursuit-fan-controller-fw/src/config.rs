@@ -0,0 +1,65 @@
+//! User-customizable configuration, persisted to flash by [`crate::persistence::Persistence`].
+//!
+//! This mirrors the shape of the previously hardcoded `STATES` table in
+//! `task::main`, but as plain, `serde`-friendly data rather than the
+//! fraction-based types used internally for the actual PWM math, so it
+//! round-trips through `postcard` cleanly and can be set over the USB
+//! serial protocol.
+
+use serde::{Deserialize, Serialize};
+
+/// Number of selectable states (what the +/- buttons cycle through).
+pub const NUM_STATES: usize = 11;
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct StateConfig {
+    /// Fan duty cycle, as a percentage (0-100).
+    pub fan_pct: u8,
+    /// Dummy-load duty cycle, as a percentage (0-100).
+    pub dummy_pct: u8,
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl StateConfig {
+    const fn new(fan_pct: u8, dummy_pct: u8, r: u8, g: u8, b: u8) -> Self {
+        StateConfig { fan_pct, dummy_pct, r, g, b }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Config {
+    pub states: [StateConfig; NUM_STATES],
+    /// LED brightness, as a percentage (0-100) applied on top of each state's RGB triple.
+    pub led_brightness_pct: u8,
+    /// How long the status LED stays lit after the last button press.
+    pub led_on_duration_secs: u16,
+}
+
+impl Default for Config {
+    /// Reproduces the values that used to be hardcoded in `task::main::STATES`.
+    fn default() -> Self {
+        Config {
+            states: [
+                StateConfig::new(5, 0, 255, 0, 0),     // red
+                StateConfig::new(10, 0, 255, 40, 0),   // orange?
+                StateConfig::new(20, 0, 255, 127, 0),  // yellow
+                StateConfig::new(30, 0, 160, 255, 0),  // light green
+                StateConfig::new(40, 0, 0, 255, 0),    // deep green
+                StateConfig::new(50, 0, 90, 0, 255),   // violet
+                StateConfig::new(60, 0, 0, 255, 255),  // teal
+                StateConfig::new(70, 0, 0, 0, 255),    // deep blue
+                StateConfig::new(80, 0, 255, 40, 40),  // salmon
+                StateConfig::new(90, 0, 255, 0, 255),  // pink
+                StateConfig::new(100, 0, 255, 255, 255), // white
+            ],
+            led_brightness_pct: 20,
+            led_on_duration_secs: 10,
+        }
+    }
+}
+
+/// Generous upper bound on the postcard-encoded size of a [`Config`], so
+/// `Persistence` can size its (de)serialization buffer statically.
+pub const CONFIG_SERIALIZED_MAX_SIZE: usize = 128;
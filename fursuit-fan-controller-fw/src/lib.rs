@@ -0,0 +1,11 @@
+#![no_std]
+
+// global logger
+use defmt_rtt as _;
+// panicking behavior
+use panic_probe as _;
+
+pub mod config;
+pub mod persistence;
+pub mod protocol;
+pub mod task;
@@ -58,8 +58,9 @@ fn main() -> ! {
     executor.run(|spawner| {
         defmt::unwrap!(spawner.spawn(task::button_poller(p.PA9, p.PA8)));
         defmt::unwrap!(spawner.spawn(task::main_task(
-            p.TIM2, p.TIM3, p.PA1, p.PA2, p.PA3, p.PA6, p.PA7, p.PB0
+            p.TIM2, p.TIM3, p.PA1, p.PA2, p.PA3, p.PA6, p.PA7, p.PB0, p.FLASH
         )));
         defmt::unwrap!(spawner.spawn(task::detect_cc(p.PA4, p.PA5, p.ADC1)));
+        defmt::unwrap!(spawner.spawn(task::usb_serial_task(p.USB, p.PA12)));
     });
 }
@@ -10,6 +10,8 @@ use embassy_stm32::{
 use embedded_storage::nor_flash::NorFlash;
 use sequential_storage::cache::NoCache;
 
+use crate::config::{CONFIG_SERIALIZED_MAX_SIZE, Config};
+
 /// How many pages of the MCU's flash should the embedded filesystem take up?
 /// Note: More pages will result in longer lifetime, since the wear on the flash
 /// will be spread across more pages.
@@ -45,6 +47,14 @@ static mut FLASH_FILESYSTEM_SECTION: [u8; FLASH_FILESYSTEM_SECTION_SIZE] =
     [0xFF; FLASH_FILESYSTEM_SECTION_SIZE];
 
 const STATE_STORAGE_KEY: u8 = 0;
+/// Set by [`Persistence::request_dfu_on_next_boot`] right before the application
+/// resets itself into the bootloader, so the bootloader knows to offer USB DFU
+/// instead of just booting the active slot. Cleared once the bootloader has read it.
+const DFU_REQUESTED_KEY: u8 = 1;
+/// Stores a postcard-encoded [`Config`] (custom fan/dummy curves, colors,
+/// brightness, LED timeout). `state_idx` is kept under its own key above so
+/// the boot-state restore stays a cheap, fixed-size fetch.
+const CONFIG_STORAGE_KEY: u8 = 2;
 
 pub struct Persistence {
     flash: BlockingAsync<Flash<'static, Blocking>>,
@@ -116,4 +126,71 @@ impl Persistence {
             }
         }
     }
+
+    pub async fn save_config(&mut self, config: &Config) {
+        let mut serialize_buffer = [0u8; CONFIG_SERIALIZED_MAX_SIZE];
+        let serialized = match postcard::to_slice(config, &mut serialize_buffer) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                defmt::warn!("Unable to serialize config: {}", e);
+                return;
+            }
+        };
+
+        let mut data_buffer = [0; 2 * <Flash as NorFlash>::ERASE_SIZE];
+        if let Err(e) = sequential_storage::map::store_item(
+            &mut self.flash,
+            self.flash_range.clone(),
+            &mut NoCache::new(),
+            &mut data_buffer,
+            &CONFIG_STORAGE_KEY,
+            &serialized,
+        )
+        .await
+        {
+            defmt::warn!("Unable to persist config to flash: {}", e);
+        }
+    }
+
+    /// Loads the persisted [`Config`], or [`Config::default`] if none is
+    /// stored yet (fresh flash) or the stored bytes fail to decode.
+    pub async fn load_config(&mut self) -> Config {
+        let mut data_buffer = [0; 2 * <Flash as NorFlash>::ERASE_SIZE];
+        match sequential_storage::map::fetch_item(
+            &mut self.flash,
+            self.flash_range.clone(),
+            &mut NoCache::new(),
+            &mut data_buffer,
+            &CONFIG_STORAGE_KEY,
+        )
+        .await
+        {
+            Ok(v) => v
+                .and_then(|bytes: &[u8]| postcard::from_bytes(bytes).ok())
+                .unwrap_or_default(),
+            Err(e) => {
+                defmt::warn!("Unable to load config from flash: {}", e);
+                Config::default()
+            }
+        }
+    }
+
+    /// Marks that the bootloader should offer USB DFU on the next boot, rather
+    /// than immediately starting the active application slot. Call this right
+    /// before resetting into the bootloader (see `MainTaskMessage::EnterDfu`).
+    pub async fn request_dfu_on_next_boot(&mut self) {
+        let mut data_buffer = [0; 2 * <Flash as NorFlash>::ERASE_SIZE];
+        if let Err(e) = sequential_storage::map::store_item(
+            &mut self.flash,
+            self.flash_range.clone(),
+            &mut NoCache::new(),
+            &mut data_buffer,
+            &DFU_REQUESTED_KEY,
+            &true,
+        )
+        .await
+        {
+            defmt::warn!("Unable to persist DFU request to flash: {}", e);
+        }
+    }
 }
@@ -0,0 +1,56 @@
+//! Wire format for the USB serial control/telemetry link.
+//!
+//! Messages are `postcard`-encoded and COBS-framed (each frame is terminated
+//! by a single `0x00` byte), so they can be read off the CDC-ACM endpoint as
+//! a plain byte stream without any additional length prefix.
+
+use serde::{Deserialize, Serialize};
+
+/// A command sent from the host to the device.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, defmt::Format)]
+pub enum HostMessage {
+    /// Set the fan duty cycle, as a percentage (0-100).
+    SetFanFraction(u8),
+    /// Set the dummy-load duty cycle, as a percentage (0-100).
+    SetDummyFraction(u8),
+    /// Set the status LED color and brightness (each channel 0-255).
+    SetColor { r: u8, g: u8, b: u8, brightness: u8 },
+    /// Set how long the status LED stays lit after the last button press, in seconds.
+    SetLedTimeoutSecs(u16),
+    /// Ask the device to send back a [`DeviceMessage::Status`].
+    RequestStatus,
+    /// Stop the outputs and reset into the DFU bootloader to accept a firmware update.
+    EnterDfu,
+}
+
+/// A response or unsolicited report sent from the device to the host.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, defmt::Format)]
+pub enum DeviceMessage {
+    /// A live snapshot of the device's state, sent in response to
+    /// [`HostMessage::RequestStatus`].
+    Status(StatusReport),
+    /// A [`HostMessage`] was received and passed on to the main task. This
+    /// does not confirm the main task acted on it: for example, `EnterDfu` is
+    /// acknowledged before it is queued and is ignored while the load is locked out.
+    Ack,
+}
+
+/// Live telemetry reported back to the host.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, defmt::Format)]
+pub struct StatusReport {
+    /// Index of the currently selected state (0 to `NUM_STATES - 1`).
+    pub state_idx: u8,
+    /// Last measured voltage on CC1, in millivolts.
+    pub cc1_mv: u16,
+    /// Last measured voltage on CC2, in millivolts.
+    pub cc2_mv: u16,
+    /// Computed supply voltage (VDDA), in millivolts.
+    pub vdda_mv: u16,
+    /// Whether the fan/dummy load are currently locked out due to insufficient USB power.
+    pub load_locked_out: bool,
+}
+
+/// Maximum size in bytes of a single postcard-encoded, COBS-framed message.
+/// Sized generously above `size_of::<HostMessage>()` / `size_of::<DeviceMessage>()`
+/// to leave headroom for future variants.
+pub const MAX_FRAME_SIZE: usize = 64;
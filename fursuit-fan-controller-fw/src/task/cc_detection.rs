@@ -1,13 +1,104 @@
+use core::cell::RefCell;
+
 use defmt::debug;
+use embassy_futures::select::{Either, select};
 use embassy_stm32::{
     Peri,
     adc::{ADC_MAX, Adc, VREF_INT},
     peripherals::{ADC1, PA4, PA5},
 };
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
 use embassy_time::{Duration, Ticker, Timer};
 
 use crate::task::{MAIN_TASK_MESSAGES, MainTaskMessage};
 
+/// `V25` (the Vsense voltage at 25 degC) from the STM32F103 datasheet, section
+/// 5.3.18. Unlike some newer STM32 families, the F103 has no per-chip TS_CAL
+/// calibration bytes in its system memory, so we use the datasheet's typical
+/// value here rather than a factory-calibrated one.
+const TEMP_SENSOR_V25_MV: i32 = 1430;
+/// `Avg_Slope` from the same datasheet section, in µV/°C.
+const TEMP_SENSOR_AVG_SLOPE_UV_PER_C: i32 = 4300;
+
+/// Converts a temperature sensor ADC sample to degrees Celsius, using the
+/// same VDDA-based millivolt conversion as the CC-line readings.
+fn convert_to_celsius(temp_sample: u16, vdda_mv: u32) -> i16 {
+    let vsense_mv = (vdda_mv * (temp_sample as u32) / ADC_MAX) as i32;
+    (((TEMP_SENSOR_V25_MV - vsense_mv) * 1000) / TEMP_SENSOR_AVG_SLOPE_UV_PER_C + 25) as i16
+}
+
+/// Latest CC-line/VDDA readings, published here so other tasks (namely the
+/// USB serial task, via [`crate::task::MainTaskMessage::RequestStatus`]) can
+/// read them without a round trip through this task's own message channel.
+static CC_TELEMETRY: Mutex<CriticalSectionRawMutex, RefCell<CcTelemetry>> =
+    Mutex::new(RefCell::new(CcTelemetry {
+        cc1_mv: 0,
+        cc2_mv: 0,
+        vdda_mv: 0,
+    }));
+
+#[derive(Clone, Copy, Default)]
+pub struct CcTelemetry {
+    pub cc1_mv: u16,
+    pub cc2_mv: u16,
+    pub vdda_mv: u16,
+}
+
+/// Returns the most recently measured CC-line voltages and VDDA.
+pub fn cc_telemetry() -> CcTelemetry {
+    CC_TELEMETRY.lock(|cell| *cell.borrow())
+}
+
+/// A raw ADC reading of a CC line, together with a validity check.
+///
+/// Mirrors the validity concept used by the RP2040 HAL's ADC driver: a
+/// conversion can come back looking numerically fine while still being
+/// garbage, either because the input is railed against the reference
+/// (saturated) or because it jumped implausibly far from the previous
+/// reading (noise, a glitch on the line, or a mid-conversion disturbance).
+/// Samples that fail [`Sample::good`] are skipped rather than fed into
+/// [`calculate_power_level`].
+#[derive(Clone, Copy)]
+struct Sample {
+    raw: u16,
+    previous: Option<u16>,
+}
+
+/// How close to `ADC_MAX` a raw reading has to be before it's considered
+/// saturated (railed against VDDA) rather than a genuine CC-line voltage.
+const SATURATION_THRESHOLD: u16 = (ADC_MAX - ADC_MAX / 64) as u16;
+/// Largest raw-code jump between consecutive samples (taken 5ms apart) that
+/// we still consider plausible for a CC line settling or a source changing
+/// its advertised current. Anything larger is treated as noise.
+const MAX_PLAUSIBLE_DELTA: u16 = (ADC_MAX / 4) as u16;
+/// How many consecutive rejected samples it takes before we give up on the
+/// current debounce run, so a handful of glitches in a row don't quietly
+/// extend it forever.
+const REJECTED_SAMPLES_RESET_THRESHOLD: u32 = 5;
+
+impl Sample {
+    fn new(raw: u16, previous: Option<Sample>) -> Self {
+        Sample {
+            raw,
+            previous: previous.map(|s| s.raw),
+        }
+    }
+
+    /// Returns `false` if this reading is saturated or an implausible jump
+    /// from the previous one, and should be skipped rather than acted on.
+    fn good(&self) -> bool {
+        if self.raw >= SATURATION_THRESHOLD {
+            return false;
+        }
+        if let Some(previous) = self.previous
+            && self.raw.abs_diff(previous) > MAX_PLAUSIBLE_DELTA
+        {
+            return false;
+        }
+        true
+    }
+}
+
 #[embassy_executor::task]
 pub async fn detect_cc(
     mut pa4: Peri<'static, PA4>,
@@ -21,8 +112,11 @@ pub async fn detect_cc(
     // Vrefint is a special channel that connects a chip-internal voltage reference
     // to the ADC. This is used to measure the actual supply voltage (VDDA) of the chip.
     let mut vrefint = adc.enable_vref();
+    // The internal temperature sensor shares its sample-and-hold circuitry with
+    // Vrefint, so it needs the same startup wait below.
+    let mut temp_sensor = adc.enable_temperature();
 
-    // Wait for Vrefint to become stable...
+    // Wait for Vrefint (and the temperature sensor) to become stable...
     Timer::after(Duration::from_millis(50)).await;
 
     // According to the datasheet (STM32F103C8, section 5.3.4), we must
@@ -50,11 +144,61 @@ pub async fn detect_cc(
     let mut last_sent_power_level = None;
     let mut candidate_power_level = None;
     let mut consecutive_readings = 0;
+    let mut rejected_readings = 0;
+    let mut last_cc1_sample: Option<Sample> = None;
+    let mut last_cc2_sample: Option<Sample> = None;
+
+    // The temperature only needs to be sampled slowly - it can't change fast
+    // enough for a faster cadence to matter - so this runs on its own, much
+    // slower, ticker alongside the 5ms CC-line one above.
+    let mut temp_ticker = Ticker::every(Duration::from_secs(1));
     loop {
-        let cc1_mv = convert_to_millivolts(adc.read(&mut pa4).await);
-        let cc2_mv = convert_to_millivolts(adc.read(&mut pa5).await);
+        let cc1_sample = Sample::new(adc.read(&mut pa4).await, last_cc1_sample);
+        let cc2_sample = Sample::new(adc.read(&mut pa5).await, last_cc2_sample);
+        last_cc1_sample = Some(cc1_sample);
+        last_cc2_sample = Some(cc2_sample);
+
+        if !cc1_sample.good() || !cc2_sample.good() {
+            debug!(
+                "Rejecting CC sample (CC1 raw {}, CC2 raw {}) as implausible",
+                cc1_sample.raw, cc2_sample.raw
+            );
+            rejected_readings += 1;
+            // Only give up on the current debounce run after several glitches
+            // in a row, so a single noisy sample doesn't restart it.
+            if rejected_readings >= REJECTED_SAMPLES_RESET_THRESHOLD {
+                consecutive_readings = 0;
+                candidate_power_level = None;
+                rejected_readings = 0;
+            }
+
+            match select(ticker.next(), temp_ticker.next()).await {
+                Either::First(()) => {}
+                Either::Second(()) => {
+                    let temp_sample = adc.read(&mut temp_sensor).await;
+                    let temp_c = convert_to_celsius(temp_sample, vdda);
+                    debug!("Measured chip temperature: {} degC", temp_c);
+                    MAIN_TASK_MESSAGES
+                        .send(MainTaskMessage::TemperatureUpdate(temp_c))
+                        .await;
+                }
+            }
+            continue;
+        }
+        rejected_readings = 0;
+
+        let cc1_mv = convert_to_millivolts(cc1_sample.raw);
+        let cc2_mv = convert_to_millivolts(cc2_sample.raw);
         // trace!("Measured CC1 = {} mV, CC2 = {} mV", cc1_mv, cc2_mv);
 
+        CC_TELEMETRY.lock(|cell| {
+            *cell.borrow_mut() = CcTelemetry {
+                cc1_mv: cc1_mv as u16,
+                cc2_mv: cc2_mv as u16,
+                vdda_mv: vdda as u16,
+            };
+        });
+
         let current_power_level = calculate_power_level(cc1_mv, cc2_mv);
 
         if Some(current_power_level) == candidate_power_level {
@@ -66,22 +210,40 @@ pub async fn detect_cc(
 
         if consecutive_readings >= 10 {
             if candidate_power_level != last_sent_power_level {
-                let enable_lockout = current_power_level == SuppliedUsbPowerLevel::Insufficient;
                 MAIN_TASK_MESSAGES
-                    .send(MainTaskMessage::SetLoadLockedOut(enable_lockout))
+                    .send(MainTaskMessage::SetAvailablePower(current_power_level))
                     .await;
                 last_sent_power_level = candidate_power_level;
             }
         }
 
-        ticker.next().await;
+        match select(ticker.next(), temp_ticker.next()).await {
+            Either::First(()) => {}
+            Either::Second(()) => {
+                let temp_sample = adc.read(&mut temp_sensor).await;
+                let temp_c = convert_to_celsius(temp_sample, vdda);
+                debug!("Measured chip temperature: {} degC", temp_c);
+                MAIN_TASK_MESSAGES
+                    .send(MainTaskMessage::TemperatureUpdate(temp_c))
+                    .await;
+            }
+        }
     }
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
-enum SuppliedUsbPowerLevel {
+/// The current budget a connected USB Type-C source is advertising on its CC
+/// line, per table 4-36 of the USB Type-C Spec Release 2.0.
+/// https://www.usb.org/sites/default/files/USB%20Type-C%20Spec%20R2.0%20-%20August%202019.pdf
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, defmt::Format)]
+pub enum SuppliedUsbPowerLevel {
+    /// Disconnected, or an ambiguous/out-of-spec reading we don't trust.
     Insufficient,
-    Sufficient,
+    /// Default USB power (vRd-USB, 500 mA).
+    Default,
+    /// 1.5 A.
+    OnePointFiveAmp,
+    /// 3 A.
+    ThreeAmp,
 }
 
 // Minimum voltage level that has to be present on a CC line for that line to be
@@ -106,12 +268,14 @@ fn calculate_power_level(cc1_mv: u32, cc2_mv: u32) -> SuppliedUsbPowerLevel {
     };
 
     // These voltage values come from the USB Type-C Spec Release 2.0, table 4-36.
-    // https://www.usb.org/sites/default/files/USB%20Type-C%20Spec%20R2.0%20-%20August%202019.pdf
-    if active_cc_mv >= 700 && active_cc_mv < 2040 {
-        // 1.5A or 3A
-        SuppliedUsbPowerLevel::Sufficient
+    if (250..=660).contains(&active_cc_mv) {
+        SuppliedUsbPowerLevel::Default
+    } else if (700..=1160).contains(&active_cc_mv) {
+        SuppliedUsbPowerLevel::OnePointFiveAmp
+    } else if (1310..=2040).contains(&active_cc_mv) {
+        SuppliedUsbPowerLevel::ThreeAmp
     } else {
-        // too low or invalid reading
+        // Too low, or in one of the ambiguous gaps between the bands above.
         SuppliedUsbPowerLevel::Insufficient
     }
 }
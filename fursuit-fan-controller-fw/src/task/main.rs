@@ -1,3 +1,4 @@
+use cortex_m::peripheral::SCB;
 use embassy_futures::select::{Either, select};
 use embassy_stm32::{
     Peri,
@@ -9,25 +10,51 @@ use embassy_stm32::{
     },
 };
 use embassy_stm32::{
-    peripherals::{ADC1, PA1, PA2, PA3, PA4, PA5, PA6, PA7, PB0, TIM2, TIM3},
+    peripherals::{ADC1, FLASH, PA1, PA2, PA3, PA4, PA5, PA6, PA7, PB0, TIM2, TIM3},
     timer::simple_pwm::SimplePwm,
 };
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
 use embassy_time::{Duration, Timer};
 
+use crate::config::{Config, NUM_STATES};
+use crate::persistence::Persistence;
+use crate::protocol::StatusReport;
+use crate::task::cc_detection::{self, SuppliedUsbPowerLevel};
+
 pub static MAIN_TASK_MESSAGES: Channel<CriticalSectionRawMutex, MainTaskMessage, 4> =
     Channel::new();
 
+/// Replies to [`MainTaskMessage::RequestStatus`], consumed by the USB serial task.
+pub static STATUS_RESPONSES: Channel<CriticalSectionRawMutex, StatusReport, 1> = Channel::new();
+
 #[derive(defmt::Format)]
 pub enum MainTaskMessage {
     PlusButtonPressed,
     MinusButtonPressed,
     EnableDummyLoad,
     DisableDummyLoad,
-    /// Initially, the load cannot be enabled. Only when enough power
-    /// is detected to be available (via the CC lines of the USB Type-C connector),
-    /// is this enabled. (It can also later be disabled again.)
-    SetLoadLockedOut(bool),
+    /// The currently negotiated USB power budget, as measured on the CC lines
+    /// of the USB Type-C connector. Initially `Insufficient`, meaning the load
+    /// is fully locked out; higher levels raise the combined fan/dummy-load
+    /// duty cycle cap instead of being all-or-nothing.
+    SetAvailablePower(SuppliedUsbPowerLevel),
+    /// Override the current state's fan percentage (0-100), set from the host over USB.
+    SetFanFraction(u8),
+    /// Override the current state's dummy-load percentage (0-100), set from the host over USB.
+    SetDummyFraction(u8),
+    /// Override the current state's LED color, and the LED brightness shared by
+    /// all states, set from the host over USB.
+    SetColor { r: u8, g: u8, b: u8, brightness: u8 },
+    /// Override how long the LED stays lit after the last button press.
+    SetLedTimeoutSecs(u16),
+    /// Host is asking for a [`StatusReport`]; reply is pushed to [`STATUS_RESPONSES`].
+    RequestStatus,
+    /// Host is requesting a firmware update: stop the outputs and reset into
+    /// the DFU bootloader.
+    EnterDfu,
+    /// A new chip temperature reading, in degrees Celsius, from `cc_detection`'s
+    /// internal temperature sensor sampling.
+    TemperatureUpdate(i16),
 }
 
 #[derive(Clone, Copy)]
@@ -83,24 +110,40 @@ impl State {
     }
 }
 
-static LED_BRIGHTNESS: U16Fraction = U16Fraction::new(2, 10);
-const STATES: [State; 11] = [
-    State::new(5, 0, 255, 0, 0).with_brightness(LED_BRIGHTNESS), // red
-    State::new(10, 0, 255, 40, 0).with_brightness(LED_BRIGHTNESS), // orange?
-    State::new(20, 0, 255, 127, 0).with_brightness(LED_BRIGHTNESS), // yellow
-    State::new(30, 0, 160, 255, 0).with_brightness(LED_BRIGHTNESS), // light green
-    State::new(40, 0, 0, 255, 0).with_brightness(LED_BRIGHTNESS),  // deep green
-    State::new(50, 0, 90, 0, 255).with_brightness(LED_BRIGHTNESS), // violet
-    State::new(60, 0, 0, 255, 255).with_brightness(LED_BRIGHTNESS), // teal
-    State::new(70, 0, 0, 0, 255).with_brightness(LED_BRIGHTNESS),  // deep blue
-    State::new(80, 0, 255, 40, 40).with_brightness(LED_BRIGHTNESS), // salmon
-    State::new(90, 0, 255, 0, 255).with_brightness(LED_BRIGHTNESS), // pink
-    State::new(100, 0, 255, 255, 255).with_brightness(LED_BRIGHTNESS), // white
-];
-
 static INITIAL_STATE_IDX: usize = 5;
 
-static LED_ON_DURATION_AFTER_BUTTON_PRESS: Duration = Duration::from_secs(10);
+/// At or above this chip temperature, the fan is forced to full speed regardless
+/// of the selected state or any host override, as a thermal safety measure.
+/// The USB power budget cap from [`max_total_duty_pct`] still applies on top.
+const THERMAL_FAN_OVERRIDE_THRESHOLD_C: i16 = 60;
+/// The temperature has to drop this many degrees below the threshold above
+/// before the thermal override is released, to avoid rapidly toggling full
+/// speed on and off around the threshold.
+const THERMAL_FAN_OVERRIDE_HYSTERESIS_C: i16 = 10;
+
+/// Combined fan+dummy-load duty cycle cap (as a percentage of max) for each
+/// negotiated USB power budget, so the fan/dummy load never try to draw more
+/// than the connected source actually advertised.
+fn max_total_duty_pct(available_power: SuppliedUsbPowerLevel) -> u16 {
+    match available_power {
+        SuppliedUsbPowerLevel::Insufficient => 0,
+        SuppliedUsbPowerLevel::Default => 30,
+        SuppliedUsbPowerLevel::OnePointFiveAmp => 70,
+        SuppliedUsbPowerLevel::ThreeAmp => 100,
+    }
+}
+
+/// Builds the runtime `STATES` table from a persisted [`Config`], applying
+/// `led_brightness_pct` to each entry's RGB triple the same way the
+/// hardcoded table used to apply the old `LED_BRIGHTNESS` constant.
+fn states_from_config(config: &Config) -> [State; NUM_STATES] {
+    let led_brightness = U16Fraction::new(config.led_brightness_pct as u16, 100);
+    core::array::from_fn(|i| {
+        let s = config.states[i];
+        State::new(s.fan_pct as u16, s.dummy_pct as u16, s.r as u16, s.g as u16, s.b as u16)
+            .with_brightness(led_brightness)
+    })
+}
 
 #[embassy_executor::task]
 pub async fn main_task(
@@ -112,7 +155,12 @@ pub async fn main_task(
     pa6: Peri<'static, PA6>,
     pa7: Peri<'static, PA7>,
     pb0: Peri<'static, PB0>,
+    flash: Peri<'static, FLASH>,
 ) -> ! {
+    let mut persistence = Persistence::new(flash);
+    let mut config = persistence.load_config().await;
+    let mut states = states_from_config(&config);
+
     let r_pin = PwmPin::new(pa1, OutputType::OpenDrain);
     let g_pin = PwmPin::new(pa2, OutputType::OpenDrain);
     let b_pin = PwmPin::new(pa3, OutputType::OpenDrain);
@@ -174,38 +222,65 @@ pub async fn main_task(
     fan.enable();
     dummy_load.enable();
 
-    let mut state_idx: usize = INITIAL_STATE_IDX;
-    let mut led_turn_off_timer: Option<Timer> =
-        Some(Timer::after(LED_ON_DURATION_AFTER_BUTTON_PRESS));
+    let mut state_idx: usize = persistence
+        .load_state()
+        .await
+        .filter(|idx| *idx < states.len())
+        .unwrap_or(INITIAL_STATE_IDX);
+    let mut led_on_duration = Duration::from_secs(config.led_on_duration_secs as u64);
+    let mut led_turn_off_timer: Option<Timer> = Some(Timer::after(led_on_duration));
     let mut dummy_enabled = false;
-    let mut load_locked_out = true;
+    let mut available_power = SuppliedUsbPowerLevel::Insufficient;
+
+    // Thermal safety override: forces the fan to full speed when the chip
+    // gets too hot, with hysteresis so it doesn't chatter around the threshold.
+    let mut thermal_fan_override_active = false;
 
     loop {
-        if !load_locked_out {
+        if available_power != SuppliedUsbPowerLevel::Insufficient {
             load_enable.set_high();
 
-            let current_state = STATES[state_idx];
+            let current_state = states[state_idx];
+            let fan_fraction = if thermal_fan_override_active {
+                U16Fraction::new(100, 100)
+            } else {
+                current_state.fan
+            };
+            let dummy_fraction = current_state.dummy;
+            let (r_fraction, g_fraction, b_fraction) =
+                (current_state.r, current_state.g, current_state.b);
+
+            // Cap the combined fan+dummy-load duty cycle so it stays within
+            // what the negotiated USB power budget can actually supply,
+            // rather than the old all-or-nothing lockout.
+            let max_total_pct = max_total_duty_pct(available_power);
+            let dummy_pct = if dummy_enabled {
+                (100 * dummy_fraction.numerator / dummy_fraction.denominator).min(max_total_pct)
+            } else {
+                0
+            };
+            let fan_pct = 100 * fan_fraction.numerator / fan_fraction.denominator;
+            let max_fan_pct = max_total_pct.saturating_sub(dummy_pct);
+            let fan_fraction = U16Fraction::new(fan_pct.min(max_fan_pct), 100);
+
             defmt::info!(
                 "Now on state {} ({}% fan, {}% dummy, dummy enabled: {})",
                 state_idx,
-                100 * current_state.fan.numerator / current_state.fan.denominator,
-                100 * current_state.dummy.numerator / current_state.dummy.denominator,
+                100 * fan_fraction.numerator / fan_fraction.denominator,
+                dummy_pct,
                 dummy_enabled
             );
 
-            fan.set_duty_cycle_fraction(current_state.fan.numerator, current_state.fan.denominator);
+            fan.set_duty_cycle_fraction(fan_fraction.numerator, fan_fraction.denominator);
             if dummy_enabled {
-                dummy_load.set_duty_cycle_fraction(
-                    current_state.dummy.numerator,
-                    current_state.dummy.denominator,
-                );
+                dummy_load.set_duty_cycle_fraction(dummy_pct, 100);
             } else {
                 dummy_load.set_duty_cycle_fully_off();
             }
             if led_turn_off_timer.is_some() {
-                r.set_duty_cycle_fraction(current_state.r.numerator, current_state.r.denominator);
-                g.set_duty_cycle_fraction(current_state.g.numerator, current_state.g.denominator);
-                b.set_duty_cycle_fraction(current_state.b.numerator, current_state.b.denominator);
+                r.set_duty_cycle_fraction(r_fraction.numerator, r_fraction.denominator);
+                g.set_duty_cycle_fraction(g_fraction.numerator, g_fraction.denominator);
+                b.set_duty_cycle_fraction(b_fraction.numerator, b_fraction.denominator);
             } else {
                 r.set_duty_cycle_fully_off();
                 g.set_duty_cycle_fully_off();
@@ -232,17 +307,19 @@ pub async fn main_task(
         match event {
             Either::First(MainTaskMessage::PlusButtonPressed) => {
                 defmt::info!("Plus button was pressed");
-                if (state_idx + 1) < STATES.len() {
+                if (state_idx + 1) < states.len() {
                     state_idx += 1;
+                    persistence.save_state(state_idx).await;
                 }
-                led_turn_off_timer = Some(Timer::after(LED_ON_DURATION_AFTER_BUTTON_PRESS));
+                led_turn_off_timer = Some(Timer::after(led_on_duration));
             }
             Either::First(MainTaskMessage::MinusButtonPressed) => {
                 defmt::info!("Minus button was pressed");
                 if state_idx >= 1 {
                     state_idx -= 1;
+                    persistence.save_state(state_idx).await;
                 }
-                led_turn_off_timer = Some(Timer::after(LED_ON_DURATION_AFTER_BUTTON_PRESS));
+                led_turn_off_timer = Some(Timer::after(led_on_duration));
             }
             Either::First(MainTaskMessage::EnableDummyLoad) => {
                 defmt::debug!("Enabling dummy load");
@@ -252,15 +329,83 @@ pub async fn main_task(
                 defmt::debug!("Disabling dummy load");
                 dummy_enabled = false;
             }
-            Either::First(MainTaskMessage::SetLoadLockedOut(locked_out)) => {
-                if locked_out {
-                    defmt::warn!(
-                        "Locking out the load since available USB power has been decreased!"
-                    )
+            Either::First(MainTaskMessage::SetAvailablePower(level)) => {
+                if level == SuppliedUsbPowerLevel::Insufficient {
+                    defmt::warn!("Locking out the load - not enough USB power is available!");
                 } else {
-                    defmt::info!("Enabling load - enough USB power is available.")
+                    defmt::info!("Available USB power changed to {}", level);
+                }
+                available_power = level;
+            }
+            Either::First(MainTaskMessage::SetFanFraction(pct)) => {
+                defmt::debug!("Host set fan to {}%", pct);
+                config.states[state_idx].fan_pct = pct.min(100);
+                states = states_from_config(&config);
+                persistence.save_config(&config).await;
+            }
+            Either::First(MainTaskMessage::SetDummyFraction(pct)) => {
+                defmt::debug!("Host set dummy load to {}%", pct);
+                config.states[state_idx].dummy_pct = pct.min(100);
+                states = states_from_config(&config);
+                persistence.save_config(&config).await;
+            }
+            Either::First(MainTaskMessage::SetColor { r: red, g: green, b: blue, brightness }) => {
+                defmt::debug!("Host set color to ({}, {}, {}) @ {}/255", red, green, blue, brightness);
+                config.states[state_idx].r = red;
+                config.states[state_idx].g = green;
+                config.states[state_idx].b = blue;
+                config.led_brightness_pct = (brightness as u16 * 100 / 255) as u8;
+                states = states_from_config(&config);
+                persistence.save_config(&config).await;
+            }
+            Either::First(MainTaskMessage::SetLedTimeoutSecs(secs)) => {
+                defmt::debug!("Host set LED timeout to {}s", secs);
+                led_on_duration = Duration::from_secs(secs as u64);
+                config.led_on_duration_secs = secs;
+                persistence.save_config(&config).await;
+            }
+            Either::First(MainTaskMessage::RequestStatus) => {
+                let telemetry = cc_detection::cc_telemetry();
+                STATUS_RESPONSES
+                    .send(StatusReport {
+                        state_idx: state_idx as u8,
+                        cc1_mv: telemetry.cc1_mv,
+                        cc2_mv: telemetry.cc2_mv,
+                        vdda_mv: telemetry.vdda_mv,
+                        load_locked_out: available_power == SuppliedUsbPowerLevel::Insufficient,
+                    })
+                    .await;
+            }
+            Either::First(MainTaskMessage::EnterDfu)
+                if available_power == SuppliedUsbPowerLevel::Insufficient =>
+            {
+                defmt::warn!("Ignoring DFU request - not enough USB power is available");
+            }
+            Either::First(MainTaskMessage::EnterDfu) => {
+                defmt::info!("Host requested DFU - stopping outputs and resetting into bootloader");
+                fan.set_duty_cycle_fully_off();
+                dummy_load.set_duty_cycle_fully_off();
+                r.set_duty_cycle_fully_off();
+                g.set_duty_cycle_fully_off();
+                b.set_duty_cycle_fully_off();
+                load_enable.set_low();
+
+                persistence.request_dfu_on_next_boot().await;
+                SCB::sys_reset();
+            }
+            Either::First(MainTaskMessage::TemperatureUpdate(temp_c)) => {
+                if !thermal_fan_override_active && temp_c >= THERMAL_FAN_OVERRIDE_THRESHOLD_C {
+                    defmt::warn!(
+                        "Chip temperature {} degC exceeds threshold - forcing fan to full speed",
+                        temp_c
+                    );
+                    thermal_fan_override_active = true;
+                } else if thermal_fan_override_active
+                    && temp_c < THERMAL_FAN_OVERRIDE_THRESHOLD_C - THERMAL_FAN_OVERRIDE_HYSTERESIS_C
+                {
+                    defmt::info!("Chip has cooled down to {} degC - releasing thermal override", temp_c);
+                    thermal_fan_override_active = false;
                 }
-                load_locked_out = locked_out;
             }
             Either::Second(()) => {
                 defmt::info!("Turning off the LED");
@@ -0,0 +1,9 @@
+mod button_poll;
+pub mod cc_detection;
+mod main;
+mod usb_serial;
+
+pub use button_poll::button_poller;
+pub use cc_detection::{SuppliedUsbPowerLevel, detect_cc};
+pub use main::{MAIN_TASK_MESSAGES, MainTaskMessage, STATUS_RESPONSES, main_task};
+pub use usb_serial::usb_serial_task;
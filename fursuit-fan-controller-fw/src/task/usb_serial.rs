@@ -0,0 +1,172 @@
+use defmt::{debug, warn};
+use embassy_stm32::{
+    Peri, bind_interrupts,
+    peripherals::{PA12, USB},
+    usb,
+};
+use embassy_usb::{
+    Builder, Config,
+    class::cdc_acm::{CdcAcmClass, State},
+    driver::EndpointError,
+};
+use heapless::Vec;
+use postcard::{from_bytes_cobs, to_vec_cobs};
+
+use crate::protocol::{DeviceMessage, HostMessage, MAX_FRAME_SIZE};
+use crate::task::{MAIN_TASK_MESSAGES, MainTaskMessage, STATUS_RESPONSES};
+
+bind_interrupts!(struct Irqs {
+    USB_LP_CAN1_RX0 => usb::InterruptHandler<USB>;
+});
+
+/// USB CDC-ACM control/telemetry link. Frames the host sends are
+/// `postcard`+COBS-encoded [`HostMessage`]s; this task decodes them, routes
+/// the resulting commands into [`MAIN_TASK_MESSAGES`], and writes back any
+/// [`DeviceMessage`] replies (such as the [`StatusReport`](crate::protocol::StatusReport)
+/// for a [`HostMessage::RequestStatus`]) the same way.
+#[embassy_executor::task]
+pub async fn usb_serial_task(usb: Peri<'static, USB>, dp: Peri<'static, PA12>) -> ! {
+    let driver = usb::Driver::new(usb, Irqs, dp);
+
+    let mut usb_config = Config::new(0xc0de, 0xcafe);
+    usb_config.manufacturer = Some("Fursuit Fan Controller");
+    usb_config.product = Some("Fan Controller Serial Console");
+    usb_config.max_power = 100;
+    usb_config.max_packet_size_0 = 64;
+
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut state = State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        usb_config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut [], // no MSOS descriptors
+        &mut control_buf,
+    );
+
+    let mut class = CdcAcmClass::new(&mut builder, &mut state, 64);
+    let mut usb_device = builder.build();
+
+    let usb_fut = usb_device.run();
+    let comms_fut = async {
+        loop {
+            class.wait_connection().await;
+            debug!("USB serial console connected");
+            if let Err(e) = handle_connection(&mut class).await {
+                warn!("USB serial console error: {}", e);
+            }
+            debug!("USB serial console disconnected");
+        }
+    };
+
+    embassy_futures::join::join(usb_fut, comms_fut).await;
+    unreachable!("embassy_usb::UsbDevice::run never returns")
+}
+
+async fn handle_connection<'d>(
+    class: &mut CdcAcmClass<'d, usb::Driver<'d, USB>>,
+) -> Result<(), EndpointError> {
+    let mut rx_buf: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+    let mut read_buf = [0; 64];
+    let mut overflowed = false;
+
+    loop {
+        let n = class.read_packet(&mut read_buf).await?;
+        for &byte in &read_buf[..n] {
+            if byte == 0x00 {
+                // COBS frame terminator: decode and dispatch what we've buffered.
+                if overflowed {
+                    warn!("Dropping oversized frame from host");
+                } else {
+                    dispatch_frame(class, &mut rx_buf).await?;
+                }
+                rx_buf.clear();
+                overflowed = false;
+                continue;
+            }
+            // Drop the frame if it overflows rather than wrapping around and
+            // decoding garbage; we'll resync on the next terminator.
+            if rx_buf.push(byte).is_err() {
+                overflowed = true;
+            }
+        }
+    }
+}
+
+async fn dispatch_frame<'d>(
+    class: &mut CdcAcmClass<'d, usb::Driver<'d, USB>>,
+    rx_buf: &mut Vec<u8, MAX_FRAME_SIZE>,
+) -> Result<(), EndpointError> {
+    if rx_buf.is_empty() {
+        return Ok(());
+    }
+
+    let Ok(message) = from_bytes_cobs::<HostMessage>(rx_buf.as_mut_slice()) else {
+        warn!("Dropping malformed frame from host");
+        return Ok(());
+    };
+    debug!("Received host message: {}", message);
+
+    match message {
+        HostMessage::SetFanFraction(pct) => {
+            MAIN_TASK_MESSAGES
+                .send(MainTaskMessage::SetFanFraction(pct))
+                .await;
+            reply(class, &DeviceMessage::Ack).await?;
+        }
+        HostMessage::SetDummyFraction(pct) => {
+            MAIN_TASK_MESSAGES
+                .send(MainTaskMessage::SetDummyFraction(pct))
+                .await;
+            reply(class, &DeviceMessage::Ack).await?;
+        }
+        HostMessage::SetColor { r, g, b, brightness } => {
+            MAIN_TASK_MESSAGES
+                .send(MainTaskMessage::SetColor { r, g, b, brightness })
+                .await;
+            reply(class, &DeviceMessage::Ack).await?;
+        }
+        HostMessage::SetLedTimeoutSecs(secs) => {
+            MAIN_TASK_MESSAGES
+                .send(MainTaskMessage::SetLedTimeoutSecs(secs))
+                .await;
+            reply(class, &DeviceMessage::Ack).await?;
+        }
+        HostMessage::RequestStatus => {
+            MAIN_TASK_MESSAGES
+                .send(MainTaskMessage::RequestStatus)
+                .await;
+            let status = STATUS_RESPONSES.receive().await;
+            reply(class, &DeviceMessage::Status(status)).await?;
+        }
+        HostMessage::EnterDfu => {
+            // Acknowledge before the reset, since the USB peripheral (and thus
+            // this connection) goes away as soon as `main_task` resets into
+            // the bootloader.
+            reply(class, &DeviceMessage::Ack).await?;
+            MAIN_TASK_MESSAGES.send(MainTaskMessage::EnterDfu).await;
+        }
+    }
+
+    Ok(())
+}
+
+async fn reply<'d>(
+    class: &mut CdcAcmClass<'d, usb::Driver<'d, USB>>,
+    message: &DeviceMessage,
+) -> Result<(), EndpointError> {
+    let mut tx_buf = [0; MAX_FRAME_SIZE];
+    let Ok(encoded) = to_vec_cobs::<_, MAX_FRAME_SIZE>(message) else {
+        warn!("Device message too large to encode, dropping reply");
+        return Ok(());
+    };
+    tx_buf[..encoded.len()].copy_from_slice(&encoded);
+    for chunk in tx_buf[..encoded.len()].chunks(64) {
+        class.write_packet(chunk).await?;
+    }
+    Ok(())
+}